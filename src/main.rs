@@ -4,34 +4,70 @@ use std::str::FromStr;
 use futures::StreamExt;
 use hyper::{Body, Request, Response, Server, Uri};
 use hyper::service::{make_service_fn, service_fn};
-use log::{debug,info, trace};
+use log::{debug,info, trace, warn};
 use serde::Deserialize;
 
-use zip::ZipWriter;
+use zip::{compute_archive_size, sanitize_entry_name, CompressionMethod, ZipWriter};
 
 mod zip;
+mod zip_crypto;
 
 #[derive(Clone, Debug, Deserialize)]
 struct ZipRequestEntry {
     url: String,
     filename: String,
+    compression: Option<CompressionMethod>,
+    password: Option<String>,
+    size: Option<u64>,
 }
 
 type ZipRequest = Vec<ZipRequestEntry>;
 
+/** whether an entry will actually be written to the archive, rather than skipped by
+`zip_request_handler`. `precompute_content_length` must agree with this exactly, or the
+response's `content-length` header won't match the bytes the write loop actually streams. */
+fn is_entry_usable(entry: &ZipRequestEntry) -> bool {
+    !sanitize_entry_name(&entry.filename).is_empty()
+}
+
+/** when every usable entry is Stored and declares its `size` up front, the exact final archive
+length can be computed without writing any bytes, so the response can carry an accurate
+Content-Length */
+fn precompute_content_length(zip_request: &ZipRequest) -> Option<u64> {
+    let entries: Option<Vec<(String, u64, bool)>> = zip_request.iter()
+        .filter(|entry| is_entry_usable(entry))
+        .map(|entry| match (entry.compression, entry.size) {
+            (Some(CompressionMethod::Store), Some(size)) => {
+                Some((entry.filename.clone(), size, entry.password.is_some()))
+            }
+            _ => None,
+        })
+        .collect();
+
+    entries.map(|entries| compute_archive_size(&entries))
+}
+
 async fn zip_request_handler(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
     let bytes = hyper::body::to_bytes(req).await?;
 
     if let Ok(zip_request) = serde_json::from_slice::<ZipRequest>(&bytes) {
         debug!("handling request");
+        let content_length = precompute_content_length(&zip_request);
         let (sender, body) = Body::channel();
 
         tokio::spawn(async move {
             let mut zip = ZipWriter::new(sender);
 
             for entry in zip_request {
+                if !is_entry_usable(&entry) {
+                    warn!("skipping entry with unsafe or empty filename {}", entry.filename);
+                    continue;
+                }
+
                 debug!("downloading file {}", entry.filename);
-                zip.start_file(&entry.filename).await.unwrap();
+                let compression_method = entry.compression.unwrap_or(CompressionMethod::Deflate);
+                zip.start_file(&entry.filename, compression_method, None, entry.password.as_deref(), entry.size)
+                    .await.unwrap();
 
                 let uri = Uri::from_str(&entry.url).unwrap();
                 let https = hyper_tls::HttpsConnector::new();
@@ -42,9 +78,21 @@ async fn zip_request_handler(req: Request<Body>) -> Result<Response<Body>, hyper
                 let body = res.body_mut();
 
                 debug!("writing file {}", entry.filename);
+                let mut downloaded_size: u64 = 0;
                 while let Some(buf) = body.next().await {
                     trace!("writing buffer");
-                    zip.write(&buf.unwrap()).await.unwrap();
+                    let buf = buf.unwrap();
+                    downloaded_size = downloaded_size + buf.len() as u64;
+                    zip.write(&buf).await.unwrap();
+                }
+
+                if let Some(declared_size) = entry.size {
+                    if declared_size != downloaded_size {
+                        warn!(
+                            "declared size {} for {} does not match downloaded size {}",
+                            declared_size, entry.filename, downloaded_size,
+                        );
+                    }
                 }
 
                 debug!("finished writing {}", entry.filename);
@@ -55,11 +103,15 @@ async fn zip_request_handler(req: Request<Body>) -> Result<Response<Body>, hyper
             let _ = zip.finish().await;
         });
 
-        let response = Response::builder()
+        let mut response_builder = Response::builder()
             .header("content-type", "application/zip")
-            .header("content-disposition", "attachment; filename=\"archive.zip\"")
-            .body(body)
-            .unwrap();
+            .header("content-disposition", "attachment; filename=\"archive.zip\"");
+
+        if let Some(content_length) = content_length {
+            response_builder = response_builder.header("content-length", content_length);
+        }
+
+        let response = response_builder.body(body).unwrap();
 
         return Ok(response);
     }
@@ -2,21 +2,197 @@ use podio::{LittleEndian, WritePodExt};
 use crc32fast::Hasher;
 use flate2::write::DeflateEncoder;
 use flate2::Compression;
+use bzip2::write::BzEncoder;
+use zstd::stream::write::Encoder as ZstdEncoder;
 use std::io::Write;
+use std::time::SystemTime;
 use hyper::body::{Sender, Bytes};
+use serde::Deserialize;
+
+use crate::zip_crypto::ZipCrypto;
+
+/// largest value representable in the legacy 32-bit size/offset fields. sizes or offsets at or
+/// beyond this must be promoted to the zip64 extra field.
+const ZIP64_THRESHOLD: u64 = 0xFFFFFFFF;
+
+/// length in bytes of the traditional PKWARE (ZipCrypto) encryption header that precedes an
+/// encrypted entry's compressed data; counted as part of the entry's compressed size per spec.
+const ZIP_CRYPTO_HEADER_SIZE: u64 = 12;
+
+/// MS-DOS date for 1980-01-01, the earliest date the format can represent
+const DOS_DATE_MIN: u16 = 1 << 5 | 1;
+
+/** pack a `SystemTime` into the MS-DOS (time, date) words used by zip headers. dates before
+1980 (the epoch of the DOS format) are clamped to the minimum representable date. */
+fn to_dos_datetime(time: SystemTime) -> (u16, u16) {
+    let epoch_seconds = match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(_) => return (0, DOS_DATE_MIN),
+    };
+
+    let days = epoch_seconds.div_euclid(86400);
+    let seconds_of_day = epoch_seconds.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+
+    if year < 1980 {
+        return (0, DOS_DATE_MIN);
+    }
+
+    let hour = seconds_of_day / 3600;
+    let minute = (seconds_of_day % 3600) / 60;
+    let second = seconds_of_day % 60;
+
+    let dos_time = ((hour as u16) << 11) | ((minute as u16) << 5) | (second as u16 / 2);
+    let dos_date = (((year - 1980) as u16) << 9) | ((month as u16) << 5) | (day as u16);
+
+    (dos_time, dos_date)
+}
+
+/// Howard Hinnant's days-since-epoch to proleptic Gregorian calendar date algorithm
+/// (http://howardhinnant.github.io/date_algorithms.html#civil_from_days)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = (z - era * 146097) as u64; // [0, 146096]
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365; // [0, 399]
+    let y = yoe as i64 + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100); // [0, 365]
+    let mp = (5 * doy + 2) / 153; // [0, 11]
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32; // [1, 31]
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32; // [1, 12]
+    (if month <= 2 { y + 1 } else { y }, month, day)
+}
+
+/** compression method for a single entry, picked by the caller of `start_file` */
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionMethod {
+    Store,
+    Deflate,
+    Bzip2,
+    Zstd,
+}
+
+impl CompressionMethod {
+    fn method_code(&self) -> u16 {
+        match self {
+            CompressionMethod::Store => 0,
+            CompressionMethod::Deflate => 8,
+            CompressionMethod::Bzip2 => 12,
+            CompressionMethod::Zstd => 93,
+        }
+    }
+
+    fn version_needed(&self) -> u16 {
+        match self {
+            CompressionMethod::Store | CompressionMethod::Deflate => 0x0014,
+            CompressionMethod::Bzip2 => 0x002e,
+            CompressionMethod::Zstd => 0x003f,
+        }
+    }
+}
 
 struct FileMetadata {
     crc32: u32,
-    uncompressed_size: u32,
-    compressed_size: u32,
-    offset: u32,
+    uncompressed_size: u64,
+    compressed_size: u64,
+    offset: u64,
     file_name: String,
+    compression_method: CompressionMethod,
+    mod_time: u16,
+    mod_date: u16,
+    external_attributes: u32,
+    encrypted: bool,
+    // whether this entry's local header and data descriptor were committed to the zip64 format.
+    // decided once, before the local header is sent, and never revised afterwards: the header
+    // and descriptor have to agree on the format, and the header goes out before the entry's
+    // final size is known.
+    uses_zip64: bool,
+}
+
+/// Unix external file attributes for a regular file with mode 0o644
+const UNIX_FILE_ATTRIBUTES: u32 = 0o100644 << 16;
+
+/// Unix external file attributes for a directory with mode 0o755, plus the MS-DOS directory bit
+const UNIX_DIR_ATTRIBUTES: u32 = (0o40755 << 16) | 0x10;
+
+/** strip any `.`, `..`, or root components from a requested entry name so archive entries can't
+escape the archive root (eg `../../etc/passwd` becomes `etc/passwd`). mirrors the directory
+handling used by the proxmox ZIP encoder. */
+pub(crate) fn sanitize_entry_name(name: &str) -> String {
+    name.split('/')
+        .filter(|component| !component.is_empty() && *component != "." && *component != "..")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn needs_zip64(file: &FileMetadata) -> bool {
+    file.uncompressed_size >= ZIP64_THRESHOLD
+        || file.compressed_size >= ZIP64_THRESHOLD
+        || file.offset >= ZIP64_THRESHOLD
+}
+
+/// the active encoder for the file currently being written. modeled on the mature `zip` crate's
+/// `GenericZipWriter`: a single enum over the supported compression methods so `ZipWriter` itself
+/// doesn't need to be generic over the encoder type.
+enum CurrentFileEncoder {
+    Store(Vec<u8>),
+    Deflate(DeflateEncoder<Vec<u8>>),
+    Bzip2(BzEncoder<Vec<u8>>),
+    Zstd(ZstdEncoder<'static, Vec<u8>>),
+}
+
+impl CurrentFileEncoder {
+    fn new(method: CompressionMethod) -> Self {
+        match method {
+            CompressionMethod::Store => CurrentFileEncoder::Store(Vec::new()),
+            CompressionMethod::Deflate => CurrentFileEncoder::Deflate(
+                DeflateEncoder::new(Vec::new(), Compression::default())
+            ),
+            CompressionMethod::Bzip2 => CurrentFileEncoder::Bzip2(
+                BzEncoder::new(Vec::new(), bzip2::Compression::default())
+            ),
+            CompressionMethod::Zstd => CurrentFileEncoder::Zstd(
+                ZstdEncoder::new(Vec::new(), 0).unwrap()
+            ),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        match self {
+            CurrentFileEncoder::Store(sink) => sink.write_all(buf),
+            CurrentFileEncoder::Deflate(encoder) => encoder.write_all(buf),
+            CurrentFileEncoder::Bzip2(encoder) => encoder.write_all(buf),
+            CurrentFileEncoder::Zstd(encoder) => encoder.write_all(buf),
+        }
+    }
+
+    /** swap out whatever compressed bytes have been produced so far */
+    fn take_buf(&mut self) -> Vec<u8> {
+        match self {
+            CurrentFileEncoder::Store(sink) => std::mem::take(sink),
+            CurrentFileEncoder::Deflate(encoder) => std::mem::take(encoder.get_mut()),
+            CurrentFileEncoder::Bzip2(encoder) => std::mem::take(encoder.get_mut()),
+            CurrentFileEncoder::Zstd(encoder) => std::mem::take(encoder.get_mut()),
+        }
+    }
+
+    /** flush and return any remaining compressed bytes */
+    fn finish(self) -> Vec<u8> {
+        match self {
+            CurrentFileEncoder::Store(sink) => sink,
+            CurrentFileEncoder::Deflate(encoder) => encoder.finish().unwrap(),
+            CurrentFileEncoder::Bzip2(encoder) => encoder.finish().unwrap(),
+            CurrentFileEncoder::Zstd(encoder) => encoder.finish().unwrap(),
+        }
+    }
 }
 
 struct CurrentFileState {
     file_metadata: FileMetadata,
     hasher: Hasher,
-    encoder: DeflateEncoder<Vec<u8>>,
+    encoder: CurrentFileEncoder,
+    cipher: Option<ZipCrypto>,
 }
 
 /**
@@ -32,14 +208,19 @@ files must be written sequentially (ie don't interleave calls to the above funct
 when all files are done:
 - call finish
 
-TODO implement zip64 extensions
 TODO reduce copying and allocation
  */
 pub struct ZipWriter {
     sender: Sender,
     file_metadata: Vec<FileMetadata>,
-    bytes_written: u32,
+    bytes_written: u64,
     current_file_state: Option<CurrentFileState>,
+    // once true, sticky for the rest of the archive: every remaining local header and data
+    // descriptor is written in zip64 format. this is set as soon as we learn (from a file's
+    // size hint or final size, or from the running archive offset) that the archive needs
+    // zip64, or when a file has no size hint at all, since a
+    // streamed local header that already went out to the sender can't be rewritten in place.
+    zip64: bool,
 }
 
 impl ZipWriter {
@@ -49,35 +230,80 @@ impl ZipWriter {
             file_metadata: Vec::new(),
             bytes_written: 0,
             current_file_state: None,
+            zip64: false,
         }
     }
 
-    /** prepares state to start writing data for a file and writes the local file header */
-    pub async fn start_file(&mut self, file_name: &str) -> Result<(), hyper::Error> {
+    /** prepares state to start writing data for a file and writes the local file header.
+    when `password` is set, the file's compressed data is additionally run through traditional
+    ZipCrypto encryption.
+
+    `size_hint`, when known (eg a remote download's Content-Length), is the entry's expected
+    uncompressed size. it decides whether this entry's local header and data descriptor are
+    committed to the zip64 format *before* the header is sent: the real compressed/uncompressed
+    sizes aren't known until the entry is fully written, by which point the header has already
+    gone out, so that decision can't be deferred to `finish_file`. when `size_hint` is `None`,
+    the entry is conservatively treated as needing zip64, since a later file that turns out to
+    exceed 4 GiB can no longer be reflected in an already-sent header. */
+    pub async fn start_file(
+        &mut self,
+        file_name: &str,
+        compression_method: CompressionMethod,
+        modified: Option<SystemTime>,
+        password: Option<&str>,
+        size_hint: Option<u64>,
+    ) -> Result<(), hyper::Error> {
         if let Some(_) = self.current_file_state {
             panic!("call finish_file before starting a new file");
         }
 
-        let file_metadata = FileMetadata {
+        if self.bytes_written >= ZIP64_THRESHOLD {
+            self.zip64 = true;
+        }
+
+        // decided once, up front: this entry's header and descriptor must agree on the format
+        self.zip64 = self.zip64 || size_hint.map_or(true, |size| size >= ZIP64_THRESHOLD);
+        let uses_zip64 = self.zip64;
+
+        let (mod_time, mod_date) = to_dos_datetime(modified.unwrap_or_else(SystemTime::now));
+
+        let mut file_metadata = FileMetadata {
             crc32: 0,
             uncompressed_size: 0,
             compressed_size: 0,
             offset: self.bytes_written,
-            file_name: file_name.into(),
+            file_name: sanitize_entry_name(file_name),
+            compression_method,
+            mod_time,
+            mod_date,
+            external_attributes: UNIX_FILE_ATTRIBUTES,
+            encrypted: password.is_some(),
+            uses_zip64,
         };
 
         // TODO avoid this buffer or buffer without allocation
         let mut buf = Vec::new();
-        let header_size = write_local_file_header(&mut buf, &file_metadata).unwrap();
+        let header_size = write_local_file_header(&mut buf, &file_metadata, uses_zip64).unwrap();
         self.sender.send_data(Bytes::from(buf)).await?;
 
+        // the encryption header is logically the start of the entry's compressed data (the spec
+        // has its length counted in the compressed size field), so its bytes are credited to
+        // bytes_written via file_metadata.compressed_size in finish_file, not here, to avoid
+        // counting them twice.
+        let cipher = if let Some(password) = password {
+            let (cipher, encryption_header) = ZipCrypto::seeded(password.as_bytes(), mod_time);
+            file_metadata.compressed_size = file_metadata.compressed_size + encryption_header.len() as u64;
+            self.sender.send_data(Bytes::from(encryption_header)).await?;
+            Some(cipher)
+        } else {
+            None
+        };
+
         self.current_file_state = Some(CurrentFileState {
             file_metadata,
             hasher: Hasher::new(),
-            encoder: DeflateEncoder::new(
-                Vec::new(),
-                Compression::default()
-            ),
+            encoder: CurrentFileEncoder::new(compression_method),
+            cipher,
         });
 
         self.bytes_written = self.bytes_written + header_size;
@@ -85,15 +311,69 @@ impl ZipWriter {
         Ok(())
     }
 
+    /** writes a zero-length stored entry for an (empty) directory. unlike `start_file`, this
+    completes the entry immediately since a directory has no data to stream.
+
+    does nothing and returns `Ok(())` if `dir_name` sanitizes to an empty path (eg `".."` or
+    `"/"`), the same unsafe/empty-name case `start_file`'s callers are expected to filter out
+    before calling it, so this can't emit a bare `"/"` entry. */
+    pub async fn start_dir(&mut self, dir_name: &str) -> Result<(), hyper::Error> {
+        if let Some(_) = self.current_file_state {
+            panic!("call finish_file before starting a new directory");
+        }
+
+        let sanitized = sanitize_entry_name(dir_name);
+        if sanitized.is_empty() {
+            return Ok(());
+        }
+
+        if self.bytes_written >= ZIP64_THRESHOLD {
+            self.zip64 = true;
+        }
+
+        let dir_name = format!("{}/", sanitized);
+        let (mod_time, mod_date) = to_dos_datetime(SystemTime::now());
+
+        let file_metadata = FileMetadata {
+            crc32: 0,
+            uncompressed_size: 0,
+            compressed_size: 0,
+            offset: self.bytes_written,
+            file_name: dir_name,
+            compression_method: CompressionMethod::Store,
+            mod_time,
+            mod_date,
+            external_attributes: UNIX_DIR_ATTRIBUTES,
+            encrypted: false,
+            uses_zip64: self.zip64,
+        };
+
+        // TODO avoid this buffer or buffer without allocation
+        let mut buf = Vec::new();
+        let header_size = write_local_file_header(&mut buf, &file_metadata, self.zip64).unwrap();
+        self.sender.send_data(Bytes::from(buf)).await?;
+
+        // a directory entry has no data, so the data descriptor immediately follows the header
+        let mut buf = Vec::new();
+        let data_descriptor_size = write_data_descriptor(&mut buf, &file_metadata, self.zip64).unwrap();
+        self.sender.send_data(Bytes::from(buf)).await?;
+
+        self.bytes_written = self.bytes_written + header_size + data_descriptor_size;
+        self.file_metadata.push(file_metadata);
+
+        Ok(())
+    }
+
     /** write part or all of file data */
     pub async fn write(&mut self, buf: &[u8]) -> Result<(), hyper::Error> {
         if let Some(CurrentFileState {
                         file_metadata,
                         hasher,
                         encoder,
+                        cipher,
                  }) = &mut self.current_file_state {
 
-            file_metadata.uncompressed_size = file_metadata.uncompressed_size + buf.len() as u32;
+            file_metadata.uncompressed_size = file_metadata.uncompressed_size + buf.len() as u64;
 
             // update the checksum
             hasher.update(buf);
@@ -102,10 +382,15 @@ impl ZipWriter {
             encoder.write_all(buf).unwrap();
 
             // swap out the encoder's buffer
-            let encoder_buf = std::mem::take(encoder.get_mut());
-            file_metadata.compressed_size = file_metadata.compressed_size + encoder_buf.len() as u32;
+            let mut encoder_buf = encoder.take_buf();
+            file_metadata.compressed_size = file_metadata.compressed_size + encoder_buf.len() as u64;
+
+            // encrypt the compressed bytes when a password is set on this entry
+            if let Some(cipher) = cipher {
+                encoder_buf = cipher.encrypt(&encoder_buf);
+            }
 
-            // send the compressed data
+            // send the compressed (and possibly encrypted) data
             self.sender.send_data(Bytes::from(encoder_buf)).await?;
 
             return Ok(())
@@ -125,12 +410,27 @@ impl ZipWriter {
         // finalize the encoder. this flushes the encoder's internal buffer and so might return
         // some data that hasn't been written to the response yet
         let remaining_data = current_file_state.encoder.finish().unwrap();
-        file_metadata.compressed_size = file_metadata.compressed_size + remaining_data.len() as u32;
+        file_metadata.compressed_size = file_metadata.compressed_size + remaining_data.len() as u64;
+
+        let remaining_data = match current_file_state.cipher {
+            Some(mut cipher) => cipher.encrypt(&remaining_data),
+            None => remaining_data,
+        };
         self.sender.send_data(Bytes::from(remaining_data)).await?;
 
+        // the data descriptor's format was already committed to in `start_file`, before the local
+        // header was sent; it must match that header regardless of what the entry's final size
+        // turned out to be. if it turns out this entry needed zip64 after all despite that not
+        // being foreseen, there's nothing to do about this entry's own header anymore, but flag
+        // it so that later entries at least get a correct header.
+        if needs_zip64(&file_metadata) {
+            self.zip64 = true;
+        }
+
         // TODO avoid this buffer or buffer without allocation
         let mut buf = Vec::new();
-        let data_descriptor_size = write_data_descriptor(&mut buf, &file_metadata).unwrap();
+        let data_descriptor_size =
+            write_data_descriptor(&mut buf, &file_metadata, file_metadata.uses_zip64).unwrap();
         self.sender.send_data(Bytes::from(buf)).await?;
 
         self.bytes_written = self.bytes_written + file_metadata.compressed_size + data_descriptor_size;
@@ -151,14 +451,44 @@ impl ZipWriter {
             self.bytes_written = self.bytes_written + bytes_written;
         }
         let size = self.bytes_written - offset;
+        let number_of_entries = self.file_metadata.len() as u64;
+
+        if self.zip64 || number_of_entries > 0xFFFF || offset >= ZIP64_THRESHOLD || size >= ZIP64_THRESHOLD {
+            let zip64_eocd_offset = self.bytes_written;
+
+            // TODO avoid this buffer or buffer without allocation
+            let mut buf = Vec::new();
+            let zip64_eocd_size = write_zip64_end_of_central_directory_record(
+                &mut buf,
+                number_of_entries,
+                offset,
+                size,
+            ).unwrap();
+            self.sender.send_data(Bytes::from(buf)).await?;
+            self.bytes_written = self.bytes_written + zip64_eocd_size;
+
+            let mut buf = Vec::new();
+            let locator_size = write_zip64_end_of_central_directory_locator(
+                &mut buf,
+                zip64_eocd_offset,
+            ).unwrap();
+            self.sender.send_data(Bytes::from(buf)).await?;
+            self.bytes_written = self.bytes_written + locator_size;
+        }
+
+        // the legacy fields are saturated rather than truncated when the real values overflow;
+        // readers that understand zip64 fall back to the zip64 end of central directory record.
+        let legacy_number_of_entries = number_of_entries.min(0xFFFF) as u16;
+        let legacy_offset = offset.min(ZIP64_THRESHOLD) as u32;
+        let legacy_size = size.min(ZIP64_THRESHOLD) as u32;
 
         // TODO avoid this buffer or buffer without allocation
         let mut buf = Vec::new();
         write_end_of_central_directory_record(
             &mut buf,
-            self.file_metadata.len() as u16,
-            offset,
-            size,
+            legacy_number_of_entries,
+            legacy_offset,
+            legacy_size,
         ).unwrap();
         self.sender.send_data(Bytes::from(buf)).await?;
 
@@ -166,6 +496,85 @@ impl ZipWriter {
     }
 }
 
+/**
+computes the exact final byte size of an archive whose entries are all Stored (uncompressed)
+and whose sizes are known up front, without writing any archive bytes. lets an HTTP handler set
+an accurate Content-Length before it starts streaming the archive.
+
+mirrors the bookkeeping `ZipWriter` itself does, entry by entry, reusing the same header-writing
+functions against `std::io::sink()` purely to measure their size. the `bool` in each tuple marks
+whether the entry is password-protected, since an encrypted entry's compressed size carries the
+12-byte ZipCrypto encryption header on top of its stored size (see `ZipWriter::start_file`).
+*/
+pub fn compute_archive_size(entries: &[(String, u64, bool)]) -> u64 {
+    let mut offset: u64 = 0;
+    let mut zip64 = false;
+    let mut file_metadata_list = Vec::with_capacity(entries.len());
+
+    for (file_name, size, encrypted) in entries {
+        if offset >= ZIP64_THRESHOLD {
+            zip64 = true;
+        }
+
+        // mirrors `ZipWriter::start_file`, which treats the declared size as its size hint
+        if *size >= ZIP64_THRESHOLD {
+            zip64 = true;
+        }
+
+        let compressed_size = size + if *encrypted { ZIP_CRYPTO_HEADER_SIZE } else { 0 };
+
+        let file_metadata = FileMetadata {
+            crc32: 0,
+            uncompressed_size: *size,
+            compressed_size,
+            offset,
+            file_name: sanitize_entry_name(file_name),
+            compression_method: CompressionMethod::Store,
+            mod_time: 0,
+            mod_date: 0,
+            external_attributes: UNIX_FILE_ATTRIBUTES,
+            encrypted: *encrypted,
+            uses_zip64: zip64,
+        };
+
+        let header_size = write_local_file_header(&mut std::io::sink(), &file_metadata, zip64).unwrap();
+
+        // mirrors `ZipWriter`: the header above already committed to `zip64`, so the descriptor
+        // must use that same value, even though `zip64` itself may flip true for later entries
+        if needs_zip64(&file_metadata) {
+            zip64 = true;
+        }
+        let data_descriptor_size =
+            write_data_descriptor(&mut std::io::sink(), &file_metadata, file_metadata.uses_zip64).unwrap();
+
+        offset = offset + header_size + compressed_size + data_descriptor_size;
+        file_metadata_list.push(file_metadata);
+    }
+
+    let central_directory_offset = offset;
+    let mut central_directory_size: u64 = 0;
+    for file_metadata in &file_metadata_list {
+        central_directory_size = central_directory_size
+            + write_central_directory_header(&mut std::io::sink(), file_metadata).unwrap();
+    }
+
+    let number_of_entries = file_metadata_list.len() as u64;
+    let mut total = central_directory_offset + central_directory_size;
+
+    if zip64
+        || number_of_entries > 0xFFFF
+        || central_directory_offset >= ZIP64_THRESHOLD
+        || central_directory_size >= ZIP64_THRESHOLD
+    {
+        total = total + 56 + 20; // zip64 end of central directory record + locator
+    }
+
+    total + 22 // end of central directory record, with no archive comment
+}
+
+/// header id for the zip64 extended information extra field
+const ZIP64_EXTRA_FIELD_ID: u16 = 0x0001;
+
 /*
    4.3.7  Local file header:
 
@@ -187,46 +596,61 @@ impl ZipWriter {
 fn write_local_file_header<W: std::io::Write>(
     writer: &mut W,
     file: &FileMetadata,
-) -> std::io::Result<u32> {
+    zip64: bool,
+) -> std::io::Result<u64> {
     // local file header signature
     writer.write_u32::<LittleEndian>(0x04034b50)?;
 
-    // version
-    writer.write_u16::<LittleEndian>(0x0014)?;
+    // version needed to extract
+    let version_needed = file.compression_method.version_needed().max(if zip64 { 0x002d } else { 0x0014 });
+    writer.write_u16::<LittleEndian>(version_needed)?;
 
     // flags
-    writer.write_u16::<LittleEndian>(1 << 3)?; // bit 3 indicates data descriptors in use
+    let flags = (1 << 3) | if file.encrypted { 1 } else { 0 }; // bit 3: data descriptor in use, bit 0: entry is encrypted
+    writer.write_u16::<LittleEndian>(flags)?;
 
     // compression method
-    writer.write_u16::<LittleEndian>(8)?; // 8 = deflate
+    writer.write_u16::<LittleEndian>(file.compression_method.method_code())?;
 
     // last mod file time
-    writer.write_u16::<LittleEndian>(0)?; // TODO
+    writer.write_u16::<LittleEndian>(file.mod_time)?;
 
     // last mod file date
-    writer.write_u16::<LittleEndian>(0)?; // TODO
+    writer.write_u16::<LittleEndian>(file.mod_date)?;
 
     // crc-32
     writer.write_u32::<LittleEndian>(0)?;
 
-    // compressed size
-    writer.write_u32::<LittleEndian>(0)?;
-
-    // uncompressed size
-    writer.write_u32::<LittleEndian>(0)?;
+    // compressed size / uncompressed size: the real sizes aren't known until the file is fully
+    // written, so these are 0 here, or 0xFFFFFFFF (pointing at the zip64 extra field) for zip64
+    if zip64 {
+        writer.write_u32::<LittleEndian>(0xFFFFFFFF)?;
+        writer.write_u32::<LittleEndian>(0xFFFFFFFF)?;
+    } else {
+        writer.write_u32::<LittleEndian>(0)?;
+        writer.write_u32::<LittleEndian>(0)?;
+    }
 
     // file name length
     let file_name = file.file_name.as_bytes();
     writer.write_u16::<LittleEndian>(file_name.len() as u16)?;
 
     // extra field length
-    writer.write_u16::<LittleEndian>(0)?;
+    let extra_field_len: u16 = if zip64 { 20 } else { 0 };
+    writer.write_u16::<LittleEndian>(extra_field_len)?;
 
     writer.write_all(file_name)?;
 
-    // extra field TODO
+    if zip64 {
+        // zip64 extended information extra field: sizes are placeholders, filled in later by
+        // the data descriptor
+        writer.write_u16::<LittleEndian>(ZIP64_EXTRA_FIELD_ID)?;
+        writer.write_u16::<LittleEndian>(16)?; // data size: two 8-byte fields
+        writer.write_u64::<LittleEndian>(0)?; // uncompressed size
+        writer.write_u64::<LittleEndian>(0)?; // compressed size
+    }
 
-    Ok(30 + file_name.len() as u32)
+    Ok(30 + file_name.len() as u64 + extra_field_len as u64)
 }
 
 /*
@@ -234,26 +658,33 @@ fn write_local_file_header<W: std::io::Write>(
 
         signature                       4 bytes (0x08074b50)
         crc-32                          4 bytes
-        compressed size                 4 bytes
-        uncompressed size               4 bytes
+        compressed size                 4 bytes (8 bytes when zip64 is in effect)
+        uncompressed size               4 bytes (8 bytes when zip64 is in effect)
  */
 fn write_data_descriptor<W: std::io::Write>(
     writer: &mut W,
     file: &FileMetadata,
-) -> std::io::Result<u32> {
+    zip64: bool,
+) -> std::io::Result<u64> {
     // local file header signature
     writer.write_u32::<LittleEndian>(0x08074b50)?;
 
     // crc-32
     writer.write_u32::<LittleEndian>(file.crc32)?;
 
-    // compressed size
-    writer.write_u32::<LittleEndian>(file.compressed_size)?;
-
-    // uncompressed size
-    writer.write_u32::<LittleEndian>(file.uncompressed_size)?;
-
-    Ok(16)
+    if zip64 {
+        // compressed size
+        writer.write_u64::<LittleEndian>(file.compressed_size)?;
+        // uncompressed size
+        writer.write_u64::<LittleEndian>(file.uncompressed_size)?;
+        Ok(24)
+    } else {
+        // compressed size
+        writer.write_u32::<LittleEndian>(file.compressed_size as u32)?;
+        // uncompressed size
+        writer.write_u32::<LittleEndian>(file.uncompressed_size as u32)?;
+        Ok(16)
+    }
 }
 
 /*
@@ -294,7 +725,15 @@ fn write_data_descriptor<W: std::io::Write>(
 fn write_central_directory_header<W: std::io::Write>(
     writer: &mut W,
     file: &FileMetadata,
-) -> std::io::Result<u32> {
+) -> std::io::Result<u64> {
+    let zip64 = needs_zip64(file);
+
+    // the zip64 extra field only carries the legacy fields that actually overflowed, in this
+    // fixed order: uncompressed size, compressed size, relative offset of local header
+    let oversized_uncompressed = file.uncompressed_size >= ZIP64_THRESHOLD;
+    let oversized_compressed = file.compressed_size >= ZIP64_THRESHOLD;
+    let oversized_offset = file.offset >= ZIP64_THRESHOLD;
+
     // signature
     writer.write_u32::<LittleEndian>(0x02014b50)?;
 
@@ -302,35 +741,44 @@ fn write_central_directory_header<W: std::io::Write>(
     writer.write_u16::<LittleEndian>((3u16 << 8) | 46u16)?; // TODO explain
 
     // version needed to extract
-    writer.write_u16::<LittleEndian>(0x0014)?;
+    let version_needed = file.compression_method.version_needed().max(if zip64 { 0x002d } else { 0x0014 });
+    writer.write_u16::<LittleEndian>(version_needed)?;
 
     // flags
-    writer.write_u16::<LittleEndian>(1 << 3)?; // bit 3 indicates data descriptors in use
+    let flags = (1 << 3) | if file.encrypted { 1 } else { 0 }; // bit 3: data descriptor in use, bit 0: entry is encrypted
+    writer.write_u16::<LittleEndian>(flags)?;
 
     // compression method
-    writer.write_u16::<LittleEndian>(8)?; // 8 = deflate
+    writer.write_u16::<LittleEndian>(file.compression_method.method_code())?;
 
     // last mod file time
-    writer.write_u16::<LittleEndian>(0)?; // TODO
+    writer.write_u16::<LittleEndian>(file.mod_time)?;
 
     // last mod file date
-    writer.write_u16::<LittleEndian>(0)?; // TODO
+    writer.write_u16::<LittleEndian>(file.mod_date)?;
 
     // crc-32
     writer.write_u32::<LittleEndian>(file.crc32)?;
 
     // compressed size
-    writer.write_u32::<LittleEndian>(file.compressed_size)?;
+    writer.write_u32::<LittleEndian>(if oversized_compressed { 0xFFFFFFFF } else { file.compressed_size as u32 })?;
 
     // uncompressed size
-    writer.write_u32::<LittleEndian>(file.uncompressed_size)?;
+    writer.write_u32::<LittleEndian>(if oversized_uncompressed { 0xFFFFFFFF } else { file.uncompressed_size as u32 })?;
 
     // file name length
     let file_name = file.file_name.as_bytes();
     writer.write_u16::<LittleEndian>(file_name.len() as u16)?;
 
     // extra field length
-    writer.write_u16::<LittleEndian>(0)?;
+    let extra_field_len: u16 = if zip64 {
+        4 + (if oversized_uncompressed { 8 } else { 0 })
+          + (if oversized_compressed { 8 } else { 0 })
+          + (if oversized_offset { 8 } else { 0 })
+    } else {
+        0
+    };
+    writer.write_u16::<LittleEndian>(extra_field_len)?;
 
     // file comment length
     writer.write_u16::<LittleEndian>(0)?;
@@ -342,18 +790,122 @@ fn write_central_directory_header<W: std::io::Write>(
     writer.write_u16::<LittleEndian>(0)?; // TODO
 
     // external file attributes
-    writer.write_u32::<LittleEndian>(0o100644 << 16)?; // TODO explain
+    writer.write_u32::<LittleEndian>(file.external_attributes)?;
 
     // relative offset of local header
-    writer.write_u32::<LittleEndian>(file.offset)?;
+    writer.write_u32::<LittleEndian>(if oversized_offset { 0xFFFFFFFF } else { file.offset as u32 })?;
 
     // file name
     writer.write_all(file_name)?;
 
-    // extra field (variable size) // TODO
+    if zip64 {
+        writer.write_u16::<LittleEndian>(ZIP64_EXTRA_FIELD_ID)?;
+        writer.write_u16::<LittleEndian>(extra_field_len - 4)?;
+        if oversized_uncompressed {
+            writer.write_u64::<LittleEndian>(file.uncompressed_size)?;
+        }
+        if oversized_compressed {
+            writer.write_u64::<LittleEndian>(file.compressed_size)?;
+        }
+        if oversized_offset {
+            writer.write_u64::<LittleEndian>(file.offset)?;
+        }
+    }
+
     // file comment (variable size) // TODO
 
-    Ok(46 + file_name.len() as u32)
+    Ok(46 + file_name.len() as u64 + extra_field_len as u64)
+}
+
+/*
+   4.3.14  Zip64 end of central directory record:
+
+      zip64 end of central dir
+      signature                       4 bytes  (0x06064b50)
+      size of zip64 end of central
+      directory record                8 bytes
+      version made by                 2 bytes
+      version needed to extract       2 bytes
+      number of this disk             4 bytes
+      number of the disk with the
+      start of the central directory  4 bytes
+      total number of entries in the
+      central directory on this disk  8 bytes
+      total number of entries in the
+      central directory               8 bytes
+      size of the central directory   8 bytes
+      offset of start of central
+      directory with respect to
+      the starting disk number        8 bytes
+ */
+fn write_zip64_end_of_central_directory_record<W: std::io::Write>(
+    writer: &mut W,
+    number_of_entries: u64,
+    offset: u64,
+    size: u64,
+) -> std::io::Result<u64> {
+    // signature
+    writer.write_u32::<LittleEndian>(0x06064b50)?;
+
+    // size of zip64 end of central directory record (not counting the signature and this field)
+    writer.write_u64::<LittleEndian>(44)?;
+
+    // version made by
+    writer.write_u16::<LittleEndian>((3u16 << 8) | 46u16)?;
+
+    // version needed to extract
+    writer.write_u16::<LittleEndian>(0x002d)?;
+
+    // number of this disk
+    writer.write_u32::<LittleEndian>(0)?;
+
+    // number of the disk with the start of the central directory
+    writer.write_u32::<LittleEndian>(0)?;
+
+    // total number of entries in the central directory on this disk
+    writer.write_u64::<LittleEndian>(number_of_entries)?;
+
+    // total number of entries in the central directory
+    writer.write_u64::<LittleEndian>(number_of_entries)?;
+
+    // size of the central directory
+    writer.write_u64::<LittleEndian>(size)?;
+
+    // offset of start of central directory with respect to the starting disk number
+    writer.write_u64::<LittleEndian>(offset)?;
+
+    Ok(56)
+}
+
+/*
+   4.3.15  Zip64 end of central directory locator:
+
+      zip64 end of central dir
+      locator signature              4 bytes  (0x07064b50)
+      number of the disk with the
+      start of the zip64 end of
+      central directory               4 bytes
+      relative offset of the zip64
+      end of central directory record 8 bytes
+      total number of disks           4 bytes
+ */
+fn write_zip64_end_of_central_directory_locator<W: std::io::Write>(
+    writer: &mut W,
+    zip64_eocd_offset: u64,
+) -> std::io::Result<u64> {
+    // signature
+    writer.write_u32::<LittleEndian>(0x07064b50)?;
+
+    // number of the disk with the start of the zip64 end of central directory
+    writer.write_u32::<LittleEndian>(0)?;
+
+    // relative offset of the zip64 end of central directory record
+    writer.write_u64::<LittleEndian>(zip64_eocd_offset)?;
+
+    // total number of disks
+    writer.write_u32::<LittleEndian>(1)?;
+
+    Ok(20)
 }
 
 /*
@@ -408,3 +960,154 @@ fn write_end_of_central_directory_record<W: std::io::Write>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_dos_datetime_round_trips_a_known_date() {
+        // 2024-01-01T01:01:01Z
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1704070861);
+        let (dos_time, dos_date) = to_dos_datetime(time);
+
+        let hour = dos_time >> 11;
+        let minute = (dos_time >> 5) & 0x3f;
+        let second = (dos_time & 0x1f) * 2;
+        assert_eq!((hour, minute, second), (1, 1, 0));
+
+        let year = (dos_date >> 9) + 1980;
+        let month = (dos_date >> 5) & 0xf;
+        let day = dos_date & 0x1f;
+        assert_eq!((year, month, day), (2024, 1, 1));
+    }
+
+    #[test]
+    fn to_dos_datetime_clamps_dates_before_the_dos_epoch() {
+        let time = SystemTime::UNIX_EPOCH - std::time::Duration::from_secs(1);
+        assert_eq!(to_dos_datetime(time), (0, DOS_DATE_MIN));
+
+        // 1979-12-31, one day before the DOS epoch
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(315532799);
+        assert_eq!(to_dos_datetime(time), (0, DOS_DATE_MIN));
+    }
+
+    #[test]
+    fn sanitize_entry_name_strips_parent_and_root_components() {
+        assert_eq!(sanitize_entry_name("../../etc/passwd"), "etc/passwd");
+        assert_eq!(sanitize_entry_name("/etc/passwd"), "etc/passwd");
+        assert_eq!(sanitize_entry_name("a/./b/../c"), "a/b/c");
+        assert_eq!(sanitize_entry_name("a//b"), "a/b");
+    }
+
+    #[test]
+    fn sanitize_entry_name_can_produce_an_empty_string() {
+        assert_eq!(sanitize_entry_name(".."), "");
+        assert_eq!(sanitize_entry_name("/"), "");
+        assert_eq!(sanitize_entry_name(""), "");
+    }
+
+    #[tokio::test]
+    async fn start_dir_skips_names_that_sanitize_to_empty() {
+        let (sender, mut body) = hyper::Body::channel();
+        tokio::spawn(async move { while let Some(_) = futures::StreamExt::next(&mut body).await {} });
+
+        let mut zip = ZipWriter::new(sender);
+        zip.start_dir("..").await.unwrap();
+        zip.start_dir("/").await.unwrap();
+
+        assert!(zip.file_metadata.is_empty());
+        assert_eq!(zip.bytes_written, 0);
+    }
+
+    #[test]
+    fn compression_method_deserializes_from_lowercase_names() {
+        assert!(matches!(
+            serde_json::from_str::<CompressionMethod>("\"store\"").unwrap(),
+            CompressionMethod::Store
+        ));
+        assert!(matches!(
+            serde_json::from_str::<CompressionMethod>("\"deflate\"").unwrap(),
+            CompressionMethod::Deflate
+        ));
+        assert!(matches!(
+            serde_json::from_str::<CompressionMethod>("\"bzip2\"").unwrap(),
+            CompressionMethod::Bzip2
+        ));
+        assert!(matches!(
+            serde_json::from_str::<CompressionMethod>("\"zstd\"").unwrap(),
+            CompressionMethod::Zstd
+        ));
+    }
+
+    #[test]
+    fn compression_method_reports_the_matching_zip_method_code_and_version_needed() {
+        assert_eq!(CompressionMethod::Store.method_code(), 0);
+        assert_eq!(CompressionMethod::Deflate.method_code(), 8);
+        assert_eq!(CompressionMethod::Bzip2.method_code(), 12);
+        assert_eq!(CompressionMethod::Zstd.method_code(), 93);
+
+        assert_eq!(CompressionMethod::Store.version_needed(), 0x0014);
+        assert_eq!(CompressionMethod::Deflate.version_needed(), 0x0014);
+        assert_eq!(CompressionMethod::Bzip2.version_needed(), 0x002e);
+        assert_eq!(CompressionMethod::Zstd.version_needed(), 0x003f);
+    }
+
+    #[tokio::test]
+    async fn start_file_does_not_commit_to_zip64_for_a_small_size_hint() {
+        let (sender, mut body) = hyper::Body::channel();
+        tokio::spawn(async move { while let Some(_) = futures::StreamExt::next(&mut body).await {} });
+
+        let mut zip = ZipWriter::new(sender);
+        zip.start_file("small.bin", CompressionMethod::Store, None, None, Some(1024)).await.unwrap();
+        assert!(!zip.zip64);
+        assert!(!zip.current_file_state.as_ref().unwrap().file_metadata.uses_zip64);
+    }
+
+    #[tokio::test]
+    async fn start_file_commits_to_zip64_when_the_size_hint_is_missing_or_large() {
+        let (sender, mut body) = hyper::Body::channel();
+        tokio::spawn(async move { while let Some(_) = futures::StreamExt::next(&mut body).await {} });
+
+        // no hint at all: conservatively assumed to need zip64, since the header can't be
+        // revised once it's been sent
+        let mut zip = ZipWriter::new(sender);
+        zip.start_file("unknown.bin", CompressionMethod::Store, None, None, None).await.unwrap();
+        assert!(zip.current_file_state.as_ref().unwrap().file_metadata.uses_zip64);
+
+        let (sender, mut body) = hyper::Body::channel();
+        tokio::spawn(async move { while let Some(_) = futures::StreamExt::next(&mut body).await {} });
+
+        // a hint at or above the threshold also commits to zip64, and that commitment sticks for
+        // later entries in the same archive
+        let mut zip = ZipWriter::new(sender);
+        zip.start_file("big.bin", CompressionMethod::Store, None, None, Some(ZIP64_THRESHOLD)).await.unwrap();
+        assert!(zip.current_file_state.as_ref().unwrap().file_metadata.uses_zip64);
+        assert!(zip.zip64);
+    }
+
+    #[tokio::test]
+    async fn compute_archive_size_matches_the_actual_streamed_archive_length() {
+        let files = [("a.txt".to_string(), vec![1u8; 10]), ("b.txt".to_string(), vec![2u8; 20])];
+
+        let entries: Vec<(String, u64, bool)> = files.iter()
+            .map(|(name, data)| (name.clone(), data.len() as u64, false))
+            .collect();
+        let expected_size = compute_archive_size(&entries);
+
+        let (sender, body) = hyper::Body::channel();
+        tokio::spawn(async move {
+            let mut zip = ZipWriter::new(sender);
+            for (name, data) in files {
+                zip.start_file(&name, CompressionMethod::Store, None, None, Some(data.len() as u64))
+                    .await.unwrap();
+                zip.write(&data).await.unwrap();
+                zip.finish_file().await.unwrap();
+            }
+            zip.finish().await.unwrap();
+        });
+
+        let actual_size = hyper::body::to_bytes(body).await.unwrap().len() as u64;
+        assert_eq!(actual_size, expected_size);
+    }
+}
@@ -0,0 +1,132 @@
+use rand::Rng;
+
+const fn build_crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            c = if c & 1 != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+            j += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+const CRC_TABLE: [u32; 256] = build_crc_table();
+
+fn crc32_update(crc: u32, byte: u8) -> u32 {
+    (crc >> 8) ^ CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize]
+}
+
+/**
+traditional PKWARE ZipCrypto stream cipher, seeded from an entry's password.
+
+for each byte of plaintext, `encrypt_byte` xors in a keystream byte derived from the current
+cipher state, then advances the state with the plaintext byte via `update_keys`.
+*/
+pub struct ZipCrypto {
+    key0: u32,
+    key1: u32,
+    key2: u32,
+}
+
+impl ZipCrypto {
+    pub fn new(password: &[u8]) -> Self {
+        let mut cipher = Self {
+            key0: 0x12345678,
+            key1: 0x23456789,
+            key2: 0x34567890,
+        };
+        for &byte in password {
+            cipher.update_keys(byte);
+        }
+        cipher
+    }
+
+    fn update_keys(&mut self, byte: u8) {
+        self.key0 = crc32_update(self.key0, byte);
+        self.key1 = self.key1.wrapping_add(self.key0 & 0xff)
+            .wrapping_mul(134775813)
+            .wrapping_add(1);
+        self.key2 = crc32_update(self.key2, (self.key1 >> 24) as u8);
+    }
+
+    fn keystream_byte(&self) -> u8 {
+        let temp = (self.key2 | 2) as u32;
+        (temp.wrapping_mul(temp ^ 1) >> 8) as u8
+    }
+
+    fn encrypt_byte(&mut self, plain: u8) -> u8 {
+        let cipher_byte = plain ^ self.keystream_byte();
+        self.update_keys(plain);
+        cipher_byte
+    }
+
+    fn decrypt_byte(&mut self, cipher: u8) -> u8 {
+        let plain = cipher ^ self.keystream_byte();
+        self.update_keys(plain);
+        plain
+    }
+
+    pub fn encrypt(&mut self, data: &[u8]) -> Vec<u8> {
+        data.iter().map(|&byte| self.encrypt_byte(byte)).collect()
+    }
+
+    pub fn decrypt(&mut self, data: &[u8]) -> Vec<u8> {
+        data.iter().map(|&byte| self.decrypt_byte(byte)).collect()
+    }
+
+    /**
+    builds a cipher seeded with `password` along with its encrypted 12-byte encryption header.
+
+    per the spec, the header's last byte is normally the high byte of the entry's crc-32, but
+    this writer always streams file data behind general-purpose bit 3 (sizes/crc aren't known
+    until the trailing data descriptor), so the high byte of the DOS last-mod time is used
+    instead, as the spec allows for that case.
+    */
+    pub fn seeded(password: &[u8], dos_time: u16) -> (Self, Vec<u8>) {
+        let mut cipher = Self::new(password);
+        let mut rng = rand::thread_rng();
+        let mut header: Vec<u8> = (0..11).map(|_| rng.gen::<u8>()).collect();
+        header.push((dos_time >> 8) as u8);
+        let encrypted_header = cipher.encrypt(&header);
+        (cipher, encrypted_header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypt_then_decrypt_round_trips() {
+        let plaintext = b"the quick brown fox jumps over the lazy dog";
+
+        let mut encrypter = ZipCrypto::new(b"hunter2");
+        let ciphertext = encrypter.encrypt(plaintext);
+        assert_ne!(ciphertext, plaintext);
+
+        let mut decrypter = ZipCrypto::new(b"hunter2");
+        let decrypted = decrypter.decrypt(&ciphertext);
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn seeded_header_decrypts_to_the_dos_time_high_byte() {
+        let dos_time: u16 = 0xabcd;
+        let (cipher, encrypted_header) = ZipCrypto::seeded(b"hunter2", dos_time);
+        assert_eq!(encrypted_header.len(), 12);
+
+        let mut decrypter = ZipCrypto::new(b"hunter2");
+        let header = decrypter.decrypt(&encrypted_header);
+        assert_eq!(header[11], (dos_time >> 8) as u8);
+
+        // seeded() returns a cipher that has already consumed the header, matching the state a
+        // reader's decrypter would be in after decrypting it
+        let _ = cipher;
+    }
+}